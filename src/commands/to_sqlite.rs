@@ -1,9 +1,10 @@
 use crate::commands::WholeStreamCommand;
 use crate::object::{Dictionary, Primitive, Value};
 use crate::prelude::*;
-use hex::encode;
-use rusqlite::{Connection, NO_PARAMS};
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{Connection, ToSql, NO_PARAMS};
 use std::io::Read;
+use std::path::PathBuf;
 
 pub struct ToSQLite;
 
@@ -22,6 +23,16 @@ impl WholeStreamCommand for ToSQLite {
 
     fn signature(&self) -> Signature {
         Signature::build("to-sqlite")
+            .optional(
+                "path",
+                SyntaxShape::Path,
+                "write into this sqlite database file instead of returning bytes",
+            )
+            .switch(
+                "append",
+                "append to `path`'s existing tables instead of recreating them",
+                None,
+            )
     }
 }
 
@@ -33,62 +44,93 @@ fn comma_concat(acc: String, current: String) -> String {
     }
 }
 
-fn get_columns(rows: &Vec<Tagged<Value>>) -> Result<String, std::io::Error> {
-    match &rows[0].item {
-        Value::Object(d) => Ok(d
-            .entries
-            .iter()
-            .map(|(k, _v)| k.clone())
-            .fold("".to_string(), comma_concat)),
-        _ => Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Could not find table column names",
-        )),
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+fn union_columns(rows: impl Iterator<Item = Vec<String>>) -> Vec<String> {
+    let mut seen = std::collections::HashSet::new();
+    let mut columns = Vec::new();
+    for keys in rows {
+        for k in keys {
+            if seen.insert(k.clone()) {
+                columns.push(k);
+            }
+        }
+    }
+    columns
+}
+
+fn get_columns(rows: &[Tagged<Value>]) -> Result<Vec<String>, std::io::Error> {
+    let mut key_lists = Vec::with_capacity(rows.len());
+    for row in rows {
+        match &row.item {
+            Value::Object(d) => key_lists.push(d.entries.iter().map(|(k, _v)| k.clone()).collect()),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "Could not find table column names",
+                ))
+            }
+        }
     }
+    Ok(union_columns(key_lists.into_iter()))
 }
 
-fn nu_value_to_sqlite_string(v: Value) -> String {
+fn nu_value_to_sql_param(v: Value) -> SqlValue {
     match v {
-        Value::Binary(u) => format!("x'{}'", encode(u)),
+        Value::Binary(u) => SqlValue::Blob(u),
         Value::Primitive(p) => match p {
-            Primitive::Nothing => "NULL".into(),
-            Primitive::Int(i) => format!("{}", i),
-            Primitive::Float(f) => format!("{}", f.into_inner()),
-            Primitive::Bytes(u) => format!("{}", u),
-            Primitive::String(s) => format!("'{}'", s.replace("'", "''")),
-            Primitive::Boolean(true) => "1".into(),
-            Primitive::Boolean(_) => "0".into(),
-            Primitive::Date(d) => format!("'{}'", d),
-            Primitive::Path(p) => format!("'{}'", p.display().to_string().replace("'", "''")),
-            Primitive::BeginningOfStream => "NULL".into(),
-            Primitive::EndOfStream => "NULL".into(),
+            Primitive::Nothing => SqlValue::Null,
+            Primitive::Int(i) => SqlValue::Integer(i),
+            Primitive::Float(f) => SqlValue::Real(f.into_inner()),
+            Primitive::Bytes(u) => SqlValue::Integer(u as i64),
+            Primitive::String(s) => SqlValue::Text(s),
+            Primitive::Boolean(true) => SqlValue::Integer(1),
+            Primitive::Boolean(_) => SqlValue::Integer(0),
+            Primitive::Date(d) => SqlValue::Text(d.to_string()),
+            Primitive::Path(p) => SqlValue::Text(p.display().to_string()),
+            Primitive::BeginningOfStream => SqlValue::Null,
+            Primitive::EndOfStream => SqlValue::Null,
         },
-        _ => "NULL".into(),
+        _ => SqlValue::Null,
     }
 }
 
-fn get_insert_values(rows: Vec<Tagged<Value>>) -> Result<String, std::io::Error> {
-    let values: Result<Vec<_>, _> = rows
-        .into_iter()
-        .map(|value| match value.item {
-            Value::Object(d) => Ok(format!(
-                "({})",
-                d.entries
-                    .iter()
-                    .map(|(_k, v)| nu_value_to_sqlite_string(v.item.clone()))
-                    .fold("".to_string(), comma_concat)
-            )),
+fn get_insert_params(
+    rows: &[Tagged<Value>],
+    columns: &[String],
+) -> Result<Vec<Vec<SqlValue>>, std::io::Error> {
+    rows.iter()
+        .map(|value| match &value.item {
+            Value::Object(d) => Ok(columns
+                .iter()
+                .map(|col| match d.entries.get(col) {
+                    Some(v) => nu_value_to_sql_param(v.item.clone()),
+                    None => SqlValue::Null,
+                })
+                .collect()),
             _ => Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
                 "Could not find table column names",
             )),
         })
-        .collect();
-    let values = values?;
-    Ok(values.into_iter().fold("".to_string(), comma_concat))
+        .collect()
 }
 
-fn generate_statements(table: Dictionary) -> Result<(String, String), std::io::Error> {
+struct TableStatements {
+    table_name: String,
+    columns: Vec<String>,
+    drop: String,
+    create: String,
+    insert: String,
+    params: Vec<Vec<SqlValue>>,
+}
+
+fn generate_statements(
+    table: Dictionary,
+    if_not_exists: bool,
+) -> Result<TableStatements, std::io::Error> {
     let table_name = match table.entries.get("table_name") {
         Some(Tagged {
             item: Value::Primitive(Primitive::String(table_name)),
@@ -101,11 +143,11 @@ fn generate_statements(table: Dictionary) -> Result<(String, String), std::io::E
             ))
         }
     };
-    let (columns, insert_values) = match table.entries.get("table_values") {
+    let rows = match table.entries.get("table_values") {
         Some(Tagged {
             item: Value::List(l),
             ..
-        }) => (get_columns(l), get_insert_values(l.to_vec())),
+        }) => l,
         _ => {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
@@ -113,39 +155,127 @@ fn generate_statements(table: Dictionary) -> Result<(String, String), std::io::E
             ))
         }
     };
-    let create = format!("create table {}({})", table_name, columns?);
-    let insert = format!("insert into {} values {}", table_name, insert_values?);
-    Ok((create, insert))
+    let columns = get_columns(rows)?;
+    let params = get_insert_params(rows, &columns)?;
+    let quoted_table = quote_ident(table_name);
+    let columns_with_types = columns
+        .iter()
+        .enumerate()
+        .map(|(i, name)| match infer_column_type(&params, i) {
+            Some(ty) => format!("{} {}", quote_ident(name), ty),
+            None => quote_ident(name),
+        })
+        .fold("".to_string(), comma_concat);
+    let create_kw = if if_not_exists {
+        "create table if not exists"
+    } else {
+        "create table"
+    };
+    let create = format!("{} {}({})", create_kw, quoted_table, columns_with_types);
+    let drop = format!("drop table if exists {}", quoted_table);
+    let quoted_columns = columns
+        .iter()
+        .map(|name| quote_ident(name))
+        .fold("".to_string(), comma_concat);
+    let placeholders = columns
+        .iter()
+        .map(|_| "?".to_string())
+        .fold("".to_string(), comma_concat);
+    let insert = format!(
+        "insert into {} ({}) values ({})",
+        quoted_table, quoted_columns, placeholders
+    );
+    Ok(TableStatements {
+        table_name: table_name.clone(),
+        columns,
+        drop,
+        create,
+        insert,
+        params,
+    })
 }
 
-fn sqlite_input_stream_to_bytes(
+fn existing_table_columns(
+    conn: &Connection,
+    table_name: &str,
+) -> Result<Option<Vec<String>>, rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!("pragma table_info({})", quote_ident(table_name)))?;
+    let columns = stmt
+        .query_map(NO_PARAMS, |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<String>, _>>()?;
+    Ok(if columns.is_empty() {
+        None
+    } else {
+        Some(columns)
+    })
+}
+
+fn columns_match(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len()
+        && a.iter().collect::<std::collections::HashSet<_>>()
+            == b.iter().collect::<std::collections::HashSet<_>>()
+}
+
+fn infer_column_type(params: &[Vec<SqlValue>], col: usize) -> Option<&'static str> {
+    let mut found = None;
+    for row in params {
+        let ty = match &row[col] {
+            SqlValue::Integer(_) => "INTEGER",
+            SqlValue::Real(_) => "REAL",
+            SqlValue::Text(_) => "TEXT",
+            SqlValue::Blob(_) => "BLOB",
+            SqlValue::Null => continue,
+        };
+        match found {
+            None => found = Some(ty),
+            Some(t) if t == ty => {}
+            Some(_) => return None,
+        }
+    }
+    found
+}
+
+fn sqlite_err(e: rusqlite::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, e)
+}
+
+fn write_rows(
+    conn: &Connection,
     values: Vec<Tagged<Value>>,
-) -> Result<Tagged<Value>, std::io::Error> {
-    // FIXME: should probably write a sqlite virtual filesystem
-    // that will allow us to use bytes as a file to avoid this
-    // write out, but this will require C code. Might be
-    // best done as a PR to rusqlite.
-    let mut tempfile = tempfile::NamedTempFile::new()?;
-    let conn = match Connection::open(tempfile.path()) {
-        Ok(conn) => conn,
-        Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
-    };
-    let tag = values[0].tag.clone();
+    append: bool,
+) -> Result<(), std::io::Error> {
     for value in values.into_iter() {
         match value.item() {
             Value::Object(d) => {
-                let (create, insert) = generate_statements(d.to_owned())?;
-                match conn
-                    .execute(&create, NO_PARAMS)
-                    .and_then(|_| conn.execute(&insert, NO_PARAMS))
-                {
-                    Ok(_) => (),
-                    Err(e) => {
-                        println!("{}", create);
-                        println!("{}", insert);
-                        println!("{:?}", e);
-                        return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
+                let statements = generate_statements(d.to_owned(), append)?;
+                if append {
+                    if let Some(existing) =
+                        existing_table_columns(conn, &statements.table_name).map_err(sqlite_err)?
+                    {
+                        if !columns_match(&existing, &statements.columns) {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::Other,
+                                format!(
+                                    "Table `{}` has columns [{}] but the incoming rows have columns [{}]",
+                                    statements.table_name,
+                                    existing.join(", "),
+                                    statements.columns.join(", ")
+                                ),
+                            ));
+                        }
                     }
+                } else {
+                    conn.execute(&statements.drop, NO_PARAMS)
+                        .map_err(sqlite_err)?;
+                }
+                conn.execute(&statements.create, NO_PARAMS)
+                    .map_err(sqlite_err)?;
+                let mut stmt = conn
+                    .prepare_cached(&statements.insert)
+                    .map_err(sqlite_err)?;
+                for row in &statements.params {
+                    let row_refs: Vec<&dyn ToSql> = row.iter().map(|v| v as &dyn ToSql).collect();
+                    stmt.execute(row_refs.as_slice()).map_err(sqlite_err)?;
                 }
             }
             other => {
@@ -156,6 +286,48 @@ fn sqlite_input_stream_to_bytes(
             }
         }
     }
+    Ok(())
+}
+
+fn sqlite_input_stream_to_file(
+    values: Vec<Tagged<Value>>,
+    path: PathBuf,
+    append: bool,
+) -> Result<(), std::io::Error> {
+    let mut conn = Connection::open(path).map_err(sqlite_err)?;
+    let tx = conn.transaction().map_err(sqlite_err)?;
+    write_rows(&tx, values, append)?;
+    tx.commit().map_err(sqlite_err)?;
+    Ok(())
+}
+
+fn sqlite_input_stream_to_bytes(
+    values: Vec<Tagged<Value>>,
+) -> Result<Tagged<Value>, std::io::Error> {
+    let tag = match values.first() {
+        Some(v) => v.tag.clone(),
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Cannot write an empty input stream to SQLite",
+            ))
+        }
+    };
+    let mut conn = Connection::open_in_memory().map_err(sqlite_err)?;
+
+    let tx = conn.transaction().map_err(sqlite_err)?;
+    write_rows(&tx, values, false)?;
+    tx.commit().map_err(sqlite_err)?;
+
+    let mut tempfile = tempfile::NamedTempFile::new()?;
+    {
+        let mut file_conn = Connection::open(tempfile.path()).map_err(sqlite_err)?;
+        let backup = rusqlite::backup::Backup::new(&conn, &mut file_conn).map_err(sqlite_err)?;
+        backup
+            .run_to_completion(5, std::time::Duration::from_millis(0), None)
+            .map_err(sqlite_err)?;
+    }
+
     let mut out = Vec::new();
     tempfile.read_to_end(&mut out)?;
     Ok(Value::Binary(out).tagged(tag))
@@ -164,20 +336,87 @@ fn sqlite_input_stream_to_bytes(
 fn to_sqlite(args: CommandArgs, registry: &CommandRegistry) -> Result<OutputStream, ShellError> {
     let args = args.evaluate_once(registry)?;
     let name_span = args.name_span();
+    let path = match args.nth(0) {
+        Some(Tagged {
+            item: Value::Primitive(Primitive::Path(p)),
+            ..
+        }) => Some(p.clone()),
+        Some(Tagged {
+            item: Value::Primitive(Primitive::String(s)),
+            ..
+        }) => Some(PathBuf::from(s)),
+        Some(Tagged { tag, .. }) => {
+            return Err(ShellError::labeled_error(
+                "Expected a file path",
+                "expected a file path",
+                tag,
+            ))
+        }
+        None => None,
+    };
+    let append = args.has("append");
+    if append && path.is_none() {
+        return Err(ShellError::labeled_error(
+            "--append requires a destination path",
+            "requires a destination path",
+            name_span,
+        ));
+    }
     let stream = async_stream_block! {
         let values: Vec<_> = args.input.into_vec().await;
-        match sqlite_input_stream_to_bytes(values) {
-            Ok(out) => {
-                yield ReturnSuccess::value(out)
-            }
-            Err(_) => {
-                yield Err(ShellError::labeled_error(
-                    "Expected an object with SQLite-compatible structure from pipeline",
+        match path {
+            Some(path) => match sqlite_input_stream_to_file(values, path, append) {
+                Ok(()) => {}
+                Err(e) => yield Err(ShellError::labeled_error(
+                    format!("{}", e),
                     "requires SQLite-compatible input",
                     name_span,
                     ))
+            },
+            None => match sqlite_input_stream_to_bytes(values) {
+                Ok(out) => {
+                    yield ReturnSuccess::value(out)
+                }
+                Err(e) => {
+                    yield Err(ShellError::labeled_error(
+                        format!("{}", e),
+                        "requires SQLite-compatible input",
+                        name_span,
+                        ))
+                }
             }
         };
     };
     Ok(stream.to_output_stream())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn union_columns_preserves_first_seen_order_across_ragged_rows() {
+        let rows = vec![
+            vec!["a".to_string(), "b".to_string()],
+            vec!["b".to_string(), "c".to_string()],
+        ];
+        assert_eq!(
+            union_columns(rows.into_iter()),
+            vec!["a".to_string(), "b".to_string(), "c".to_string()]
+        );
+    }
+
+    #[test]
+    fn columns_match_ignores_order() {
+        let existing = vec!["a".to_string(), "b".to_string()];
+        let incoming = vec!["b".to_string(), "a".to_string()];
+        assert!(columns_match(&existing, &incoming));
+    }
+
+    #[test]
+    fn columns_match_rejects_different_sets() {
+        let existing = vec!["a".to_string(), "b".to_string()];
+        let incoming = vec!["a".to_string(), "c".to_string()];
+        assert!(!columns_match(&existing, &incoming));
+    }
+}